@@ -1,20 +1,100 @@
+use std::cell::RefCell;
 use std::env;
 use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::thread;
 use std::process;
 use std::cmp::max;
-use std::io::{self, Write};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, IsTerminal, Read, Write};
+use std::fmt;
 use std::ops::{Add, AddAssign};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-#[derive(Clone, Copy)]
-struct Position {
-    x: i32,
-    y: i32
+// Every character the run loop dispatches on. Anything else aborts the
+// program when the instruction pointer lands on it.
+const INSTRUCTIONS: &str = "hHjJkKlLaArRdDsSfFeEgGpPxXq";
+
+// How many steps run between two looks at the clock when a timeout is set.
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
+
+#[derive(Debug)]
+pub enum ArghError {
+    EmptyProgram,
+    StartOutOfBounds { x: i32, y: i32 },
+    CycleDetected { step: u64 },
+    StepLimit { limit: u64 },
+    Timeout { elapsed: Duration },
+    Interrupted,
+    StackUnderflow,
+    NoMatchBeforeEdge,
+    StackOverflow { limit: usize },
+    OutOfBounds,
+    LeftCodebox,
+    UnknownInstruction(char),
+    InvalidCell(i32),
+    Io(io::Error)
+}
+
+// Exit codes used by the command line front-end.
+pub const EXIT_HALTED: i32 = 0;
+pub const EXIT_ARGH: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_LIMIT: i32 = 3;
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+impl ArghError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ArghError::EmptyProgram | ArghError::StartOutOfBounds { .. } => EXIT_USAGE,
+            ArghError::StepLimit { .. } | ArghError::Timeout { .. } => EXIT_LIMIT,
+            ArghError::Interrupted => EXIT_INTERRUPTED,
+            _ => EXIT_ARGH
+        }
+    }
+}
+
+impl fmt::Display for ArghError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArghError::EmptyProgram => write!(f, "the program is empty, there is nothing to run"),
+            ArghError::StartOutOfBounds { x, y } => {
+                write!(f, "start position (x: {}, y: {}) is outside the codebox", x, y)
+            }
+            ArghError::CycleDetected { step } => {
+                write!(f, "infinite loop detected at step {}, this state was already seen", step)
+            }
+            ArghError::StepLimit { limit } => write!(f, "step limit of {} reached", limit),
+            ArghError::Timeout { elapsed } => {
+                write!(f, "timed out after {:.3} seconds", elapsed.as_secs_f64())
+            }
+            ArghError::Interrupted => write!(f, "interrupted"),
+            ArghError::StackUnderflow => write!(f, "stack underflow"),
+            ArghError::StackOverflow { limit } => write!(f, "stack overflow, the limit is {} values", limit),
+            ArghError::OutOfBounds => write!(f, "the neighbouring cell is outside the codebox"),
+            ArghError::LeftCodebox => write!(f, "the instruction pointer left the codebox"),
+            ArghError::UnknownInstruction(c) => write!(f, "'{}' is not an instruction", c.escape_debug()),
+            ArghError::InvalidCell(value) => write!(f, "the cell holds {}, which is not a character", value),
+            ArghError::Io(err) => write!(f, "IO error: {}", err),
+            ArghError::NoMatchBeforeEdge => {
+                write!(f, "reached the edge of the codebox without finding the top of the stack")
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub struct Position {
+    pub x: i32,
+    pub y: i32
 }
 
-impl Position {
-    #[allow(dead_code)]
-    pub fn to_string(&self) -> String {
-        format!("x: {}, y: {}", self.x, self.y)
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "x: {}, y: {}", self.x, self.y)
     }
 }
 
@@ -46,8 +126,8 @@ impl AddAssign<Direction> for Position {
     }
 }
 
-#[derive(Clone, Copy)]
-struct Direction {
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub struct Direction {
     xoff: i8,
     yoff: i8
 }
@@ -58,43 +138,144 @@ impl Direction {
     pub const WEST:  Direction = { Direction { xoff: -1, yoff:  0 }};
     pub const EAST:  Direction = { Direction { xoff:  1, yoff:  0 }};
 
-    #[allow(dead_code)]
-    pub fn to_string(&self) -> String {
+    pub fn turn_cw(&self) -> Direction {
+        Direction { xoff: -self.yoff, yoff: self.xoff }
+    }
+
+    pub fn turn_ccw(&self) -> Direction {
+        Direction { xoff: self.yoff, yoff: -self.xoff }
+    }
+
+    pub fn reverse(&self) -> Direction {
+        Direction { xoff: -self.xoff, yoff: -self.yoff }
+    }
+}
 
-        match (self.xoff, self.yoff) {
-            ( 0, -1)  => String::from("North"),
-            ( 0,  1)  => String::from("South"),
-            (-1,  0)  => String::from("West"),
-            ( 1,  0)  => String::from("East"),
-            _         => String::from("NOT A VALID DIRECTION!")
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Direction::NORTH => write!(f, "North"),
+            Direction::SOUTH => write!(f, "South"),
+            Direction::WEST  => write!(f, "West"),
+            Direction::EAST  => write!(f, "East"),
+            _                => write!(f, "NOT A VALID DIRECTION!")
         }
     }
 }
 
-struct Codebox {
+#[derive(Clone)]
+pub struct Codebox {
     codebox: Vec<Vec<i32>>
 }
 
+// The source is rewritten while it is loaded, so coordinates always refer
+// to the grid after these transformations, not to the raw file.
+pub struct CodeboxOptions {
+    // Expand tabs to spaces up to the next multiple of this width. With
+    // None every tab stays a single literal cell.
+    pub tab_width: Option<usize>,
+    // Drop every line whose first non-space character starts this prefix
+    // before the grid is built, so later lines move up.
+    pub comment_prefix: Option<String>,
+    // Fills the end of rows that are shorter than the longest one.
+    pub padding: char,
+    // Load the source byte by byte with from_bytes. None of the options
+    // above apply then.
+    pub binary: bool
+}
+
+impl Default for CodeboxOptions {
+    fn default() -> CodeboxOptions {
+        CodeboxOptions { tab_width: Some(8), comment_prefix: None, padding: ' ', binary: false }
+    }
+}
+
 impl Codebox {
-    pub fn new(file_string: &String) -> Codebox {
-        let mut new_codebox = Codebox { codebox: Vec::new() };
+    pub fn new(file_string: &str) -> Codebox {
+        Codebox::with_options(file_string, &CodeboxOptions::default())
+    }
+
+    pub fn with_options(file_string: &str, options: &CodeboxOptions) -> Codebox {
+        if options.binary {
+            return Codebox::from_bytes(file_string.as_bytes());
+        }
+
+        let rows = file_string.lines().filter_map(|line| Codebox::parse_line(line, options)).collect();
+        Codebox::from_rows(rows, options.padding as i32)
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> io::Result<Codebox> {
+        Codebox::from_reader_with_options(reader, &CodeboxOptions::default())
+    }
 
-        let mut max_width: usize = 0;
+    // Reads the program a line at a time, so the source text is never held
+    // in memory as a whole. The rows themselves still have to be kept until
+    // the longest one is known and the others can be padded, which takes 4
+    // bytes per cell, more than the text for ASCII programs.
+    pub fn from_reader_with_options<R: BufRead>(reader: R, options: &CodeboxOptions) -> io::Result<Codebox> {
+        let mut rows = Vec::new();
 
-        for line in file_string.lines() {
+        for line in reader.lines() {
+            if let Some(row) = Codebox::parse_line(&line?, options) {
+                rows.push(row);
+            }
+        }
+
+        Ok(Codebox::from_rows(rows, options.padding as i32))
+    }
 
-            let mut row = Vec::new();
-            for c in line.chars() {
+    // Returns None for a line that is dropped as a comment.
+    fn parse_line(line: &str, options: &CodeboxOptions) -> Option<Vec<i32>> {
+        // lines() only removes a '\r' that comes before a '\n', not the one
+        // ending a last line that has no '\n'.
+        let line = line.strip_suffix('\r').unwrap_or(line);
 
-                row.push(c as i32);
+        if let Some(ref prefix) = options.comment_prefix {
+            if line.trim_start_matches(' ').starts_with(prefix.as_str()) {
+                return None;
             }
-            max_width = max(max_width, row.len());
-            new_codebox.codebox.push(row);
         }
 
+        let mut row = Vec::new();
+        for c in line.chars() {
+            match (c, options.tab_width) {
+                ('\t', Some(tab_width)) if tab_width > 0 => {
+                    let stop = (row.len() / tab_width + 1) * tab_width;
+                    while row.len() < stop {
+                        row.push(' ' as i32);
+                    }
+                }
+                _ => row.push(c as i32)
+            }
+        }
+        Some(row)
+    }
+
+    // Every byte becomes one cell holding its value, and only '\n' splits
+    // rows. Unlike the text loader, there is no UTF-8 decoding (a multi-byte
+    // character takes several cells, each ≥ 128), no tab expansion and '\r'
+    // is kept as a cell.
+    pub fn from_bytes(bytes: &[u8]) -> Codebox {
+        let mut rows: Vec<Vec<i32>> = bytes
+            .split(|b| *b == b'\n')
+            .map(|line| line.iter().map(|b| *b as i32).collect())
+            .collect();
+
+        if bytes.ends_with(b"\n") {
+            rows.pop();
+        }
+
+        Codebox::from_rows(rows, ' ' as i32)
+    }
+
+    fn from_rows(rows: Vec<Vec<i32>>, padding: i32) -> Codebox {
+        let mut new_codebox = Codebox { codebox: rows };
+
+        let max_width = new_codebox.codebox.iter().map(|row| row.len()).fold(0, max);
+
         for line in new_codebox.codebox.iter_mut() {
             while line.len() < max_width {
-                line.push(' ' as i32);
+                line.push(padding);
             }
         }
 
@@ -102,260 +283,1566 @@ impl Codebox {
     }
 
     pub fn get_instruction(&self, position: &Position) -> Option<&i32> {
-        let row = self.codebox.get(position.y as usize);
-        if row == None { return None }
-        row.unwrap().get(position.x as usize)
+        if position.x < 0 || position.y < 0 { return None }
+        self.codebox.get(position.y as usize)?.get(position.x as usize)
     }
 
     pub fn set_instruction(&mut self, position: &Position, instruction: i32) -> Result<i8, i8> {
-        let last_instruction = self.get_instruction(position);
-        if last_instruction == None { return Err(1) }
+        if position.x < 0 || position.y < 0 { return Err(1) }
+        if self.get_instruction(position).is_none() { return Err(1) }
         self.codebox[position.y as usize][position.x as usize] = instruction;
         Ok(0)
     }
 
+    // Lays the characters of `s` out one per cell, starting at `start` and
+    // going towards `direction`. Nothing is written unless all of it fits.
+    pub fn write_string(&mut self, start: Position, direction: Direction, s: &str) -> Result<(), ArghError> {
+        let mut cells = Vec::new();
+        let mut position = start;
+
+        for c in s.chars() {
+            if self.get_instruction(&position).is_none() { return Err(ArghError::OutOfBounds); }
+            cells.push((position, c as i32));
+            position += direction;
+        }
+
+        for (position, value) in cells {
+            self.set_instruction(&position, value).map_err(|_| ArghError::OutOfBounds)?;
+        }
+        Ok(())
+    }
+
+    pub fn width(&self) -> usize {
+        self.codebox.first().map_or(0, |row| row.len())
+    }
+
+    pub fn height(&self) -> usize {
+        self.codebox.len()
+    }
+
+    pub fn cell(&self, x: usize, y: usize) -> Option<char> {
+        self.codebox.get(y)?.get(x).map(|c| Codebox::i32_as_char(*c))
+    }
+
+    // Lists the cells that would abort the program if they were executed.
+    // Whitespace is left out since it mostly pads lines and separates code.
+    pub fn scan_instructions(&self) -> Vec<(Position, char)> {
+        let mut suspicious = Vec::new();
+
+        for (y, row) in self.codebox.iter().enumerate() {
+            for (x, cell) in row.iter().enumerate() {
+                let c = Codebox::i32_as_char(*cell);
+                if !c.is_whitespace() && !INSTRUCTIONS.contains(c) {
+                    suspicious.push((Position { x: x as i32, y: y as i32 }, c));
+                }
+            }
+        }
+        suspicious
+    }
+
+    // Only a hint: f/F/g/G can still write a 'q' while the program runs, and
+    // a 'q' that is there might never be reached.
+    pub fn has_quit(&self) -> bool {
+        self.codebox.iter().any(|row| row.contains(&('q' as i32)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codebox.iter().flatten().all(|c| Codebox::i32_as_char(*c).is_whitespace())
+    }
+
+    // One line per row with every cell as a hex number. A value outside
+    // 0..=255 widens all columns so they stay aligned, negative values keep
+    // their sign.
+    pub fn hexdump(&self) -> String {
+        let hex = |value: i32| {
+            if value < 0 { format!("-{:x}", -(value as i64)) } else { format!("{:02x}", value) }
+        };
+        let width = self.codebox.iter().flatten().map(|c| hex(*c).len()).fold(2, max);
+
+        let mut s = String::new();
+        for row in self.codebox.iter() {
+            let cells: Vec<String> = row.iter().map(|c| format!("{:>1$}", hex(*c), width)).collect();
+            s.push_str(&cells.join(" "));
+            s.push('\n');
+        }
+        s
+    }
+
+    // Cells hold Unicode scalar values, 0 and everything above 255 included.
+    // Anything that is not a valid char (negative values, surrogates,
+    // > 0x10FFFF) reads as a space.
     pub fn i32_as_char(val: i32) -> char {
-        if val < 255 && val > 0 {
-            return (val as u8) as char;
+        if val >= 0 {
+            if let Some(c) = char::from_u32(val as u32) {
+                return c;
+            }
         }
         ' '
     }
+}
 
-    #[allow(dead_code)]
-    pub fn to_string(&self) -> String {
-        let mut s = String::from("");
-
+impl fmt::Display for Codebox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for lines in self.codebox.iter() {
             for c in lines.iter() {
-                s.push(Codebox::i32_as_char(*c))
+                write!(f, "{}", Codebox::i32_as_char(*c))?;
             }
-            s.push('\n')
+            writeln!(f)?;
         }
-        s
+        Ok(())
     }
 }
 
-struct Interpreter {
+const NO_QUIT_WARNING: &str =
+    "there is no 'q' in the program, so it cannot halt unless one is written while it runs";
+
+// A program as it was loaded: the raw source, the codebox built from it and
+// the file it came from, if any.
+pub struct Program {
+    source: Vec<u8>,
+    codebox: Codebox,
+    path: Option<PathBuf>
+}
+
+impl Program {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Program> {
+        Program::from_file_with_options(path, &CodeboxOptions::default())
+    }
+
+    pub fn from_file_with_options<P: AsRef<Path>>(path: P, options: &CodeboxOptions) -> io::Result<Program> {
+        let mut program = Program::from_source(fs::read(path.as_ref())?, options);
+        program.path = Some(path.as_ref().to_path_buf());
+        Ok(program)
+    }
+
+    // Sources that are not valid UTF-8 can only be loaded byte by byte.
+    pub fn from_source(source: Vec<u8>, options: &CodeboxOptions) -> Program {
+        let codebox = match std::str::from_utf8(&source) {
+            Ok(text) => Codebox::with_options(text, options),
+            Err(_)   => Codebox::from_bytes(&source)
+        };
+        Program { source, codebox, path: None }
+    }
+
+    pub fn source(&self) -> &[u8] {
+        &self.source
+    }
+
+    pub fn codebox(&self) -> &Codebox {
+        &self.codebox
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn width(&self) -> usize {
+        self.codebox.width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.codebox.height()
+    }
+
+    // Everything worth warning about before the program runs.
+    pub fn check(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if !self.codebox.has_quit() && !self.codebox.is_empty() {
+            warnings.push(String::from(NO_QUIT_WARNING));
+        }
+        for (position, c) in self.codebox.scan_instructions() {
+            warnings.push(format!("'{}' at ({}) is not an instruction", c.escape_debug(), position));
+        }
+        warnings
+    }
+}
+
+impl std::str::FromStr for Program {
+    type Err = std::convert::Infallible;
+
+    fn from_str(source: &str) -> Result<Program, Self::Err> {
+        Ok(Program::from_source(source.as_bytes().to_vec(), &CodeboxOptions::default()))
+    }
+}
+
+impl From<Program> for Codebox {
+    fn from(program: Program) -> Codebox {
+        program.codebox
+    }
+}
+
+#[derive(Clone)]
+pub struct InterpreterState {
+    pub codebox: Codebox,
+    pub stack: Vec<i32>,
+    pub position: Position,
+    pub direction: Direction,
+    pub running: bool,
+    pub steps: u64,
+    pub input: Option<VecDeque<i32>>,
+    pub input_ended: bool,
+    pub lines_read: usize,
+    pub seen: HashSet<(Position, Direction, Vec<i32>)>
+}
+
+// Handed to the observer after every instruction. `position` is the cell
+// that was executed, `direction` and `stack` are the state it left behind.
+pub struct StepContext<'a> {
+    pub step: u64,
+    pub position: Position,
+    pub direction: Direction,
+    pub instruction: char,
+    pub stack: &'a [i32]
+}
+
+type Observer = dyn FnMut(&StepContext);
+
+// What Steps yields for every instruction. It owns a copy of the stack,
+// since the interpreter keeps running after the item is handed out.
+#[derive(Clone, Debug)]
+pub struct StepRecord {
+    pub step: u64,
+    pub position: Position,
+    pub direction: Direction,
+    pub instruction: char,
+    pub stack: Vec<i32>
+}
+
+pub struct Interpreter {
     codebox: Codebox,
     direction: Direction,
     position: Position,
     stack: Vec<i32>,
-    input: Option<String>,
-    running: bool
+    stack_limit: Option<usize>,
+    input: Option<VecDeque<i32>>,
+    input_ended: bool,
+    input_log: Option<Vec<String>>,
+    lines_read: usize,
+    eof: i32,
+    reader: Box<dyn BufRead>,
+    // Only p/P write here. Everything the interpreter reports itself goes
+    // to stderr, so the output can be piped on untouched.
+    writer: Box<dyn Write>,
+    running: bool,
+    flush_each: bool,
+    numeric_output: bool,
+    wrap: bool,
+    trace_from: Option<u64>,
+    detect_cycle: bool,
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+    interrupt: Option<&'static AtomicBool>,
+    profile: Option<HashMap<char, u64>>,
+    observer: Option<Box<Observer>>,
+    seen: HashSet<(Position, Direction, Vec<i32>)>,
+    // Only set when there is a timeout. Instant::now() panics on targets
+    // without a clock, such as wasm32-unknown-unknown.
+    started: Option<Instant>,
+    steps: u64,
+    chars_written: u64,
+    bytes_written: u64
 }
 
 impl Interpreter {
 
-    pub fn new(codebox: Codebox) -> Interpreter {
+    pub fn new<C: Into<Codebox>>(codebox: C) -> Interpreter {
+        Interpreter::with_io(codebox, BufReader::new(InterruptibleStdin), BufWriter::new(io::stdout()))
+    }
+
+    // Never creates a handle on stdin or stdout, for hosts that must not
+    // touch the process's IO.
+    pub fn with_io<C, R, W>(codebox: C, reader: R, writer: W) -> Interpreter
+        where C: Into<Codebox>, R: BufRead + 'static, W: Write + 'static
+    {
         Interpreter {
-            codebox:   codebox,
-            direction: Direction::EAST,
-            position:  Position {x: 0, y: 0},
-            stack:     Vec::new(),
-            input:     None,
-            running:   false,
+            codebox:       codebox.into(),
+            direction:     Direction::EAST,
+            position:      Position {x: 0, y: 0},
+            stack:         Vec::new(),
+            stack_limit:   None,
+            input:         None,
+            input_ended:   false,
+            input_log:     None,
+            lines_read:    0,
+            eof:           0,
+            reader:        Box::new(reader),
+            writer:        Box::new(writer),
+            running:       false,
+            flush_each:    false,
+            numeric_output: false,
+            wrap:          false,
+            trace_from:    None,
+            detect_cycle:  false,
+            max_steps:     None,
+            timeout:       None,
+            interrupt:     None,
+            profile:       None,
+            observer:      None,
+            seen:          HashSet::new(),
+            started:       None,
+            steps:         0,
+            chars_written: 0,
+            bytes_written: 0,
         }
     }
 
-    pub fn run(&mut self) {
-        self.running = true;
+    pub fn with_wrap(mut self, wrap: bool) -> Interpreter {
+        self.wrap = wrap;
+        self
+    }
+
+    pub fn with_start_position(mut self, position: Position) -> Result<Interpreter, ArghError> {
+        if self.codebox.get_instruction(&position).is_none() {
+            return Err(ArghError::StartOutOfBounds { x: position.x, y: position.y });
+        }
+        self.position = position;
+        Ok(self)
+    }
+
+    pub fn with_start_direction(mut self, direction: Direction) -> Interpreter {
+        self.direction = direction;
+        self
+    }
+
+    pub fn with_input<R: BufRead + 'static>(mut self, reader: R) -> Interpreter {
+        self.reader = Box::new(reader);
+        self
+    }
+
+    pub fn with_output<W: Write + 'static>(mut self, writer: W) -> Interpreter {
+        self.writer = Box::new(writer);
+        self
+    }
+
+    pub fn with_eof(mut self, eof: i32) -> Interpreter {
+        self.eof = eof;
+        self
+    }
+
+    pub fn with_flush_each(mut self, flush_each: bool) -> Interpreter {
+        self.flush_each = flush_each;
+        self
+    }
+
+    pub fn with_numeric_output(mut self, numeric_output: bool) -> Interpreter {
+        self.numeric_output = numeric_output;
+        self
+    }
+
+    pub fn with_max_steps(mut self, max_steps: Option<u64>) -> Interpreter {
+        self.max_steps = max_steps;
+        self
+    }
+
+    pub fn with_stack_limit(mut self, stack_limit: Option<usize>) -> Interpreter {
+        self.stack_limit = stack_limit;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Interpreter {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_interrupt(mut self, interrupt: &'static AtomicBool) -> Interpreter {
+        self.interrupt = Some(interrupt);
+        self
+    }
+
+    pub fn with_profiling(mut self, profile: bool) -> Interpreter {
+        self.profile = if profile { Some(HashMap::new()) } else { None };
+        self
+    }
+
+    // Keeps every input line read, so that after restoring a snapshot the
+    // lines read since are handed out again. Costs memory for the whole run.
+    pub fn with_input_log(mut self, log: bool) -> Interpreter {
+        self.input_log = if log { Some(Vec::new()) } else { None };
+        self
+    }
+
+    pub fn with_observer<F: FnMut(&StepContext) + 'static>(mut self, observer: F) -> Interpreter {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    pub fn with_trace(mut self, trace_from: Option<u64>) -> Interpreter {
+        self.trace_from = trace_from;
+        self
+    }
+
+    pub fn with_cycle_detection(mut self, detect_cycle: bool) -> Interpreter {
+        self.detect_cycle = detect_cycle;
+        self
+    }
+
+    pub fn run(&mut self) -> Result<(), ArghError> {
+        let result = self.execute();
+        let flushed = self.writer.flush();
+        result?;
+        flushed.map_err(ArghError::Io)
+    }
+
+    fn execute(&mut self) -> Result<(), ArghError> {
+        self.start()?;
         while self.running {
-            let instruction = self.codebox.get_instruction(&self.position);
+            self.step()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn start(&mut self) -> Result<(), ArghError> {
+        if self.codebox.is_empty() {
+            return Err(ArghError::EmptyProgram);
+        }
+
+        self.seen.clear();
+        self.started = self.timeout.map(|_| Instant::now());
+        self.running = true;
+        Ok(())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
 
-            if instruction == None {
-                self.argh()
+    pub fn steps(self) -> Steps {
+        Steps { interpreter: self, started: false, finished: false }
+    }
+
+    pub fn step(&mut self) -> Result<(), ArghError> {
+        if let Some(limit) = self.max_steps {
+            if self.steps >= limit {
+                return Err(ArghError::StepLimit { limit });
             }
+        }
 
-            match Codebox::i32_as_char(*instruction.unwrap()) {
-                'h' => self.r#move(Direction::WEST),
-                'H' => self.move_until(Direction::WEST),
-                'j' => self.r#move(Direction::SOUTH),
-                'J' => self.move_until(Direction::SOUTH),
-                'k' => self.r#move(Direction::NORTH),
-                'K' => self.move_until(Direction::NORTH),
-                'l' => self.r#move(Direction::EAST),
-                'L' => self.move_until(Direction::EAST),
-                'a' => self.stack_add(Direction::SOUTH),
-                'A' => self.stack_add(Direction::NORTH),
-                'r' => self.stack_reduce(Direction::SOUTH),
-                'R' => self.stack_reduce(Direction::NORTH),
-                'd' => self.stack_dupe(),
-                'D' => self.stack_drop(),
-                's' => self.stack_push(Direction::SOUTH),
-                'S' => self.stack_push(Direction::NORTH),
-                'f' => self.alter_codebox(Direction::SOUTH),
-                'F' => self.alter_codebox(Direction::NORTH),
-                'e' => self.place_eof(Direction::SOUTH),
-                'E' => self.place_eof(Direction::NORTH),
-                'g' => self.get_input(Direction::SOUTH),
-                'G' => self.get_input(Direction::NORTH),
-                'p' => self.print(Direction::SOUTH),
-                'P' => self.print(Direction::NORTH),
-                'x' => self.turn_right(),
-                'X' => self.turn_left(),
-
-                'q' => self.quit(),
-                 _  => self.argh()
+        if self.interrupted() {
+            return Err(ArghError::Interrupted);
+        }
+
+        if let (Some(timeout), Some(started)) = (self.timeout, self.started) {
+            if self.steps.is_multiple_of(TIMEOUT_CHECK_INTERVAL) && started.elapsed() >= timeout {
+                return Err(ArghError::Timeout { elapsed: started.elapsed() });
             }
+        }
 
-            self.advance();
+        let instruction = self.codebox.get_instruction(&self.position).copied();
+
+        if instruction.is_none() {
+            return Err(ArghError::LeftCodebox);
+        }
+
+        // f/F may have stored any value here. Report it as it is instead of
+        // letting i32_as_char turn it into a space.
+        let value = instruction.unwrap();
+        if char::from_u32(value as u32).is_none() {
+            return Err(ArghError::InvalidCell(value));
+        }
+
+        let instruction = Codebox::i32_as_char(value);
+
+        if let Some(trace_from) = self.trace_from {
+            if self.steps >= trace_from {
+                eprintln!("{}", self.trace_line());
+            }
+        }
+
+        if let Some(profile) = self.profile.as_mut() {
+            *profile.entry(instruction).or_insert(0) += 1;
+        }
+
+        // Output and codebox writes change the observable state, so only
+        // a repeat since the last one of those proves the program is stuck.
+        if self.detect_cycle {
+            match instruction {
+                'p' | 'P' | 'f' | 'F' | 'e' | 'E' | 'g' | 'G' => self.seen.clear(),
+                _ => {
+                    if !self.seen.insert((self.position, self.direction, self.stack.clone())) {
+                        return Err(ArghError::CycleDetected { step: self.steps });
+                    }
+                }
+            }
+        }
+
+        // H/J/K/L move the pointer before the observer sees it.
+        let position = self.position;
+
+        match instruction {
+            'h' => self.r#move(Direction::WEST),
+            'H' => self.move_until(Direction::WEST)?,
+            'j' => self.r#move(Direction::SOUTH),
+            'J' => self.move_until(Direction::SOUTH)?,
+            'k' => self.r#move(Direction::NORTH),
+            'K' => self.move_until(Direction::NORTH)?,
+            'l' => self.r#move(Direction::EAST),
+            'L' => self.move_until(Direction::EAST)?,
+            'a' => self.stack_add(Direction::SOUTH)?,
+            'A' => self.stack_add(Direction::NORTH)?,
+            'r' => self.stack_reduce(Direction::SOUTH)?,
+            'R' => self.stack_reduce(Direction::NORTH)?,
+            'd' => self.stack_dupe()?,
+            'D' => self.stack_drop()?,
+            's' => self.stack_push(Direction::SOUTH)?,
+            'S' => self.stack_push(Direction::NORTH)?,
+            'f' => self.alter_codebox(Direction::SOUTH)?,
+            'F' => self.alter_codebox(Direction::NORTH)?,
+            'e' => self.place_eof(Direction::SOUTH)?,
+            'E' => self.place_eof(Direction::NORTH)?,
+            'g' => self.get_input(Direction::SOUTH)?,
+            'G' => self.get_input(Direction::NORTH)?,
+            'p' => self.print(Direction::SOUTH)?,
+            'P' => self.print(Direction::NORTH)?,
+            'x' => self.turn_right()?,
+            'X' => self.turn_left()?,
+
+            'q' => self.quit(),
+             _  => return Err(ArghError::UnknownInstruction(instruction))
+        }
+
+        if let Some(observer) = self.observer.as_mut() {
+            observer(&StepContext {
+                step:        self.steps,
+                position,
+                direction:   self.direction,
+                instruction,
+                stack:       &self.stack,
+            });
+        }
+
+        self.advance();
+        self.steps += 1;
+
+        Ok(())
+    }
+
+    pub fn codebox(&self) -> &Codebox {
+        &self.codebox
+    }
+
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    pub fn stack(&self) -> &[i32] {
+        &self.stack
+    }
+
+    pub fn steps_executed(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn chars_written(&self) -> u64 {
+        self.chars_written
+    }
+
+    // Characters above 127 take more than one byte in the UTF-8 output, a
+    // number printed with --numeric-output takes its digits and a space.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    pub fn snapshot(&self) -> InterpreterState {
+        InterpreterState {
+            codebox:     self.codebox.clone(),
+            stack:       self.stack.clone(),
+            position:    self.position,
+            direction:   self.direction,
+            running:     self.running,
+            steps:       self.steps,
+            input:       self.input.clone(),
+            input_ended: self.input_ended,
+            lines_read:  self.lines_read,
+            seen:        self.seen.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, state: InterpreterState) {
+        self.codebox = state.codebox;
+        self.stack = state.stack;
+        self.position = state.position;
+        self.direction = state.direction;
+        self.running = state.running;
+        self.steps = state.steps;
+        self.input = state.input;
+        self.input_ended = state.input_ended;
+        self.seen = state.seen;
+        self.lines_read = state.lines_read;
+    }
+
+    pub fn profile(&self) -> Option<&HashMap<char, u64>> {
+        self.profile.as_ref()
+    }
+
+    // Draws the codebox with the cell under the instruction pointer wrapped
+    // in `open` and `close`, e.g. terminal escapes or brackets.
+    pub fn render_codebox(&self, open: &str, close: &str) -> String {
+        let mut s = String::new();
+
+        for (y, row) in self.codebox.codebox.iter().enumerate() {
+            for (x, c) in row.iter().enumerate() {
+                let c = Codebox::i32_as_char(*c);
+                if self.position == (Position { x: x as i32, y: y as i32 }) {
+                    s.push_str(open);
+                    s.push(c);
+                    s.push_str(close);
+                } else {
+                    s.push(c);
+                }
+            }
+            s.push('\n');
         }
+        s
+    }
+
+    pub fn trace_line(&self) -> String {
+        let instruction = self.codebox.get_instruction(&self.position)
+            .map_or(' ', |c| Codebox::i32_as_char(*c));
+
+        format!(
+            "step {}: position: ({}), direction: {}, instruction: '{}', stack: {:?}",
+            self.steps,
+            self.position,
+            self.direction,
+            instruction.escape_debug(),
+            self.stack
+        )
+    }
+
+    // The codebox is written as one string per row, so a front-end can show
+    // it without decoding cell values.
+    pub fn state_json(&self) -> String {
+        let rows: Vec<String> = self.codebox.codebox.iter()
+            .map(|row| row.iter().map(|c| Codebox::i32_as_char(*c)).collect::<String>())
+            .map(|row| json_string(&row))
+            .collect();
+        let stack: Vec<String> = self.stack.iter().map(|value| value.to_string()).collect();
+
+        format!(
+            concat!(
+                "{{\"codebox\":[{}],\"stack\":[{}],\"position\":{{\"x\":{},\"y\":{}}},",
+                "\"direction\":{},\"running\":{}}}"
+            ),
+            rows.join(","),
+            stack.join(","),
+            self.position.x,
+            self.position.y,
+            json_string(&self.direction.to_string()),
+            self.running
+        )
+    }
+
+    fn interrupted(&self) -> bool {
+        self.interrupt.is_some_and(|interrupt| interrupt.load(Ordering::Relaxed))
     }
 
     fn advance(&mut self) {
-        self.position += self.direction;
+        self.position = self.wrapped(self.position + self.direction);
+    }
+
+    // With --wrap, a position past an edge continues at the opposite one.
+    fn wrapped(&self, position: Position) -> Position {
+        let width = self.codebox.width() as i32;
+        let height = self.codebox.height() as i32;
+        if self.wrap && width > 0 && height > 0 {
+            Position { x: position.x.rem_euclid(width), y: position.y.rem_euclid(height) }
+        } else {
+            position
+        }
     }
 
     fn r#move(&mut self, direction: Direction) {
         self.direction = direction;
     }
 
-    fn move_until(&mut self, direction: Direction) {
-        self.r#move(direction);
-        self.advance();
-        let mut stack_last: Option<&i32>;
-        let mut instruction: Option<&i32>;
+    fn move_until(&mut self, direction: Direction) -> Result<(), ArghError> {
+        let target = match self.stack.last() {
+            Some(value) => *value,
+            None        => return Err(ArghError::StackUnderflow)
+        };
+
+        // Without --wrap the edge ends the scan. With it, one lap along the
+        // row or column visits every cell in it, so there is no point going on.
+        let length = if direction.xoff != 0 { self.codebox.width() } else { self.codebox.height() };
+        let start = self.position;
 
-        loop {
-            stack_last = self.stack.last();
-            instruction = self.codebox.get_instruction(&self.position);
+        self.r#move(direction);
 
-            if stack_last == None || instruction == None {
-                self.argh()
+        for _ in 0..length {
+            self.advance();
+            match self.codebox.get_instruction(&self.position) {
+                Some(instruction) if *instruction == target => return Ok(()),
+                Some(_) => {}
+                None    => break
             }
+        }
+
+        // The error is reported at the instruction that scanned, not at the
+        // edge it gave up on.
+        self.position = start;
+        Err(ArghError::NoMatchBeforeEdge)
+    }
 
-            if stack_last.unwrap() == instruction.unwrap() {
-                break;
+    fn push(&mut self, value: i32) -> Result<(), ArghError> {
+        if let Some(limit) = self.stack_limit {
+            if self.stack.len() >= limit {
+                return Err(ArghError::StackOverflow { limit });
             }
+        }
+        self.stack.push(value);
+        Ok(())
+    }
 
-            self.advance();
+    // Every instruction that reads or writes the cell above or below goes
+    // through these two, so the out-of-bounds policy lives in one place.
+    fn neighbor(&self, direction: Direction) -> Result<i32, ArghError> {
+        match self.codebox.get_instruction(&self.wrapped(self.position + direction)) {
+            Some(value) => Ok(*value),
+            None        => Err(ArghError::OutOfBounds)
         }
     }
 
-    fn stack_add(&mut self, direction: Direction) {
-        let instruction = self.codebox.get_instruction(&(self.position + direction));
+    fn set_neighbor(&mut self, direction: Direction, value: i32) -> Result<(), ArghError> {
+        let position = self.wrapped(self.position + direction);
+        match self.codebox.set_instruction(&position, value) {
+            Ok(_)  => Ok(()),
+            Err(_) => Err(ArghError::OutOfBounds)
+        }
+    }
+
+    fn stack_add(&mut self, direction: Direction) -> Result<(), ArghError> {
+        let instruction = self.neighbor(direction)?;
         let stack_val = self.stack.pop();
-        if instruction == None || stack_val == None { self.argh(); }
-        self.stack.push(*instruction.unwrap() + stack_val.unwrap());
+        if stack_val.is_none() { return Err(ArghError::StackUnderflow); }
+        self.push(instruction + stack_val.unwrap())
     }
 
-    fn stack_reduce(&mut self, direction: Direction) {
-        let instruction = self.codebox.get_instruction(&(self.position + direction));
+    fn stack_reduce(&mut self, direction: Direction) -> Result<(), ArghError> {
+        let instruction = self.neighbor(direction)?;
         let stack_val = self.stack.pop();
-        if instruction == None || stack_val == None { self.argh(); }
-        self.stack.push(stack_val.unwrap() - instruction.unwrap());
+        if stack_val.is_none() { return Err(ArghError::StackUnderflow); }
+        self.push(stack_val.unwrap() - instruction)
     }
 
-    fn stack_dupe(&mut self) {
-        let instruction_opt = self.stack.last();
-        if instruction_opt == None { self.argh(); }
-        let instruction = *instruction_opt.unwrap();
-        self.stack.push(instruction);
+    fn stack_dupe(&mut self) -> Result<(), ArghError> {
+        let instruction_opt = self.stack.last().copied();
+        if instruction_opt.is_none() { return Err(ArghError::StackUnderflow); }
+        let instruction = instruction_opt.unwrap();
+        self.push(instruction)
     }
 
-    fn stack_drop(&mut self) {
+    fn stack_drop(&mut self) -> Result<(), ArghError> {
         let instruction = self.stack.pop();
-        if instruction == None { self.argh(); }
+        if instruction.is_none() { return Err(ArghError::StackUnderflow); }
+        Ok(())
     }
 
-    fn stack_push(&mut self, direction: Direction) {
-        let instruction = self.codebox.get_instruction(&(self.position + direction));
-        if instruction == None { self.argh(); }
-        self.stack.push(*instruction.unwrap());
+    fn stack_push(&mut self, direction: Direction) -> Result<(), ArghError> {
+        let instruction = self.neighbor(direction)?;
+        self.push(instruction)
     }
 
-    fn alter_codebox(&mut self, direction: Direction) {
+    fn alter_codebox(&mut self, direction: Direction) -> Result<(), ArghError> {
         let instruction = self.stack.pop();
-        if instruction == None { self.argh(); }
-        let res = self.codebox.set_instruction(&(self.position + direction), instruction.unwrap());
-        if res.is_err() { self.argh() }
+        if instruction.is_none() { return Err(ArghError::StackUnderflow); }
+        self.set_neighbor(direction, instruction.unwrap())
     }
 
-    fn place_eof(&mut self, direction: Direction) {
-        let res = self.codebox.set_instruction(&(self.position + direction), 0);
-        if res.is_err() { self.argh() }
+    fn place_eof(&mut self, direction: Direction) -> Result<(), ArghError> {
+        self.set_neighbor(direction, self.eof)
     }
 
-    fn get_input(&mut self, direction: Direction) {
-        if self.input == None {
-            let mut temp_string = String::new();
-            let res = io::stdin().read_line(&mut temp_string);
-            if res.is_err() { self.argh(); }
-            temp_string.push('\0');
-            self.input = Some(temp_string);
+    // Input is read a line at a time. Each line is handed out character by
+    // character, including its '\n', followed by the EOF value. The read
+    // after that leaves the cell alone and only lets go of the line. Once the
+    // stream has ended it is not read again and every read gives the EOF
+    // value, so a program reading past the end never blocks.
+    fn get_input(&mut self, direction: Direction) -> Result<(), ArghError> {
+        if self.input_ended {
+            return self.set_neighbor(direction, self.eof);
         }
 
-        let character = self.input.as_ref().unwrap().chars().next();
-        if character == None {
-            self.input = None;
+        if self.input.is_none() {
+            // A line logged after the snapshot that was restored is handed
+            // out again instead of reading a new one.
+            let logged = self.input_log.as_ref().and_then(|log| log.get(self.lines_read)).cloned();
+            let temp_string = match logged {
+                Some(logged) => logged,
+                None         => {
+                    let mut temp_string = String::new();
+                    let res = self.writer.flush().and_then(|_| self.reader.read_line(&mut temp_string));
+                    match res {
+                        Err(_) if self.interrupted() => return Err(ArghError::Interrupted),
+                        Err(err) => return Err(ArghError::Io(err)),
+                        Ok(0)    => {
+                            self.input_ended = true;
+                            return self.set_neighbor(direction, self.eof);
+                        }
+                        Ok(_)    => {}
+                    }
+                    if let Some(log) = self.input_log.as_mut() {
+                        log.push(temp_string.clone());
+                    }
+                    temp_string
+                }
+            };
+            self.lines_read += 1;
+
+            let mut line: VecDeque<i32> = temp_string.chars().map(|c| c as i32).collect();
+            line.push_back(self.eof);
+            self.input = Some(line);
         }
-        else {
-            let res = self.codebox.set_instruction(&(self.position + direction), character.unwrap() as i32);
-            if res.is_err() { self.argh() }
-            self.input.as_mut().unwrap().remove(0);
+
+        let character = self.input.as_mut().unwrap().pop_front();
+        if character.is_none() {
+            self.input = None;
+            return Ok(());
         }
+        self.set_neighbor(direction, character.unwrap())
     }
 
-    fn print(&self, direction: Direction) {
-        let instruction = self.codebox.get_instruction(&(self.position + direction));
-        if instruction == None { self.argh(); }
-        print!("{}", Codebox::i32_as_char(*instruction.unwrap()));
-        io::stdout().flush().unwrap();
-    }
+    fn print(&mut self, direction: Direction) -> Result<(), ArghError> {
+        let instruction = self.neighbor(direction)?;
+        let character = Codebox::i32_as_char(instruction);
+        let mut buffer = [0; 4];
+        let number;
+        let text: &str = if self.numeric_output {
+            number = format!("{} ", instruction);
+            &number
+        } else {
+            character.encode_utf8(&mut buffer)
+        };
 
-    fn turn_right(&mut self) {
-        if *self.stack.last().unwrap() > 0 {
-            match (self.direction.xoff, self.direction.yoff) {
-                ( 0, -1)  => self.direction = Direction::EAST,
-                ( 0,  1)  => self.direction = Direction::WEST,
-                ( 1,  0)  => self.direction = Direction::SOUTH,
-                (-1,  0)  => self.direction = Direction::NORTH,
-                _         => self.argh()
+        // A failed write leaves the counters alone. Under --numeric-output a
+        // printed cell is still one character, but as many bytes as its
+        // number takes.
+        let mut res = self.writer.write_all(text.as_bytes());
+        if res.is_ok() {
+            self.chars_written += 1;
+            self.bytes_written += text.len() as u64;
+            if self.flush_each || character == '\n' {
+                res = self.writer.flush();
             }
         }
+        res.map_err(ArghError::Io)
     }
 
-    fn turn_left(&mut self) {
-        if *self.stack.last().unwrap() < 0 {
-            match (self.direction.xoff, self.direction.yoff) {
-                ( 0, -1)  => self.direction = Direction::WEST,
-                ( 0,  1)  => self.direction = Direction::EAST,
-                ( 1,  0)  => self.direction = Direction::NORTH,
-                (-1,  0)  => self.direction = Direction::SOUTH,
-                _         => self.argh()
-            }
+    fn turn_right(&mut self) -> Result<(), ArghError> {
+        let top = self.stack.last().copied();
+        if top.is_none() { return Err(ArghError::StackUnderflow); }
+        if top.unwrap() > 0 {
+            self.direction = self.direction.turn_cw();
         }
+        Ok(())
+    }
+
+    fn turn_left(&mut self) -> Result<(), ArghError> {
+        let top = self.stack.last().copied();
+        if top.is_none() { return Err(ArghError::StackUnderflow); }
+        if top.unwrap() < 0 {
+            self.direction = self.direction.turn_ccw();
+        }
+        Ok(())
     }
 
     fn quit(&mut self) {
         self.running = false;
     }
 
-    fn argh(&self) {
-        println!("\nAargh!!");
-        process::exit(1);
+    // Formats an error the way the command line reports it, pointing at the
+    // cell the instruction pointer was on when it happened.
+    pub fn describe_error(&self, err: &ArghError) -> String {
+        // A cell that does not hold a character has nothing to quote.
+        let instruction = self.codebox.get_instruction(&self.position)
+            .filter(|value| char::from_u32(**value as u32).is_some());
+
+        match err {
+            ArghError::EmptyProgram | ArghError::StartOutOfBounds { .. } => format!("Aargh! {}", err),
+            _ => match instruction {
+                Some(instruction) => format!(
+                    "Aargh! at ({},{}) heading {}: '{}' — {}",
+                    self.position.x,
+                    self.position.y,
+                    self.direction,
+                    Codebox::i32_as_char(*instruction).escape_debug(),
+                    err
+                ),
+                None => format!(
+                    "Aargh! at ({},{}) heading {} — {}",
+                    self.position.x,
+                    self.position.y,
+                    self.direction,
+                    err
+                )
+            }
+        }
     }
+}
 
-    #[allow(dead_code)]
-    pub fn to_string(&self) -> String{
-        format!(
+impl fmt::Display for Interpreter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
             "{}\nPosition: {}\nDirection: {}",
-            self.codebox.to_string(),
-            self.position.to_string(),
-            self.direction.to_string()
+            self.codebox,
+            self.position,
+            self.direction
         )
     }
 }
 
+// Runs the program one instruction per item. It ends after the program
+// halts or after the first error, which is yielded as the last item.
+pub struct Steps {
+    interpreter: Interpreter,
+    started: bool,
+    finished: bool
+}
+
+impl Iterator for Steps {
+    type Item = Result<StepRecord, ArghError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        if !self.started {
+            self.started = true;
+            if let Err(err) = self.interpreter.start() {
+                self.finished = true;
+                return Some(Err(err));
+            }
+        }
+
+        if !self.interpreter.is_running() {
+            self.finished = true;
+            return None;
+        }
+
+        let step = self.interpreter.steps;
+        let position = self.interpreter.position;
+        let instruction = self.interpreter.codebox.get_instruction(&position)
+            .map_or(' ', |c| Codebox::i32_as_char(*c));
+
+        if let Err(err) = self.interpreter.step() {
+            self.finished = true;
+            return Some(Err(err));
+        }
+
+        Some(Ok(StepRecord {
+            step,
+            position,
+            direction: self.interpreter.direction,
+            instruction,
+            stack:     self.interpreter.stack.clone(),
+        }))
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"'  => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c    => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// A writer that keeps everything in memory and can be cloned, so the
+// output can still be read after the interpreter has taken ownership.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Runs a program entirely in memory. Nothing here touches stdin, stdout or
+// process::exit, so it is safe to call from a host without a terminal,
+// e.g. a WebAssembly build.
+pub fn run_to_string(program: &str, input: &str, max_steps: u64) -> Result<String, ArghError> {
+    let output = SharedBuffer::default();
+    let codebox = Codebox::with_options(program, &CodeboxOptions::default());
+
+    let mut interpreter = Interpreter::with_io(codebox, Cursor::new(input.as_bytes().to_vec()), output.clone())
+        .with_max_steps(Some(max_steps));
+
+    interpreter.run()?;
+    Ok(String::from_utf8_lossy(&output.contents()).into_owned())
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// The standard library retries a read that a signal interrupted, so a g/G
+// or debugger prompt waiting on stdin would keep waiting after Ctrl-C. This
+// gives up on such a read instead once INTERRUPTED is set.
+struct InterruptibleStdin;
+
+impl Read for InterruptibleStdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match io::stdin().read(buf) {
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted && INTERRUPTED.load(Ordering::Relaxed) => {
+                Err(io::Error::other("interrupted"))
+            }
+            result => result
+        }
+    }
+}
+
+#[cfg(any(
+    all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")),
+    all(target_os = "macos", any(target_arch = "x86_64", target_arch = "aarch64"))
+))]
+extern "C" fn on_interrupt(_signal: i32) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+// struct sigaction is written out by hand, so it is only used where its
+// layout has been checked: 64-bit glibc and musl on x86_64 and aarch64, and
+// macOS on both. Other architectures order or pad the fields differently
+// (MIPS puts sa_flags first, 32-bit ARM pads after sa_handler).
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[repr(C)]
+struct SigAction {
+    sa_handler: extern "C" fn(i32),
+    sa_mask: [u64; 16],
+    sa_flags: i32,
+    sa_restorer: usize
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn interrupt_action() -> SigAction {
+    const SA_RESETHAND: i32 = 0x8000_0000_u32 as i32;
+    SigAction { sa_handler: on_interrupt, sa_mask: [0; 16], sa_flags: SA_RESETHAND, sa_restorer: 0 }
+}
+
+#[cfg(all(target_os = "macos", any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[repr(C)]
+struct SigAction {
+    sa_handler: extern "C" fn(i32),
+    sa_mask: u32,
+    sa_flags: i32
+}
+
+#[cfg(all(target_os = "macos", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn interrupt_action() -> SigAction {
+    const SA_RESETHAND: i32 = 0x4;
+    SigAction { sa_handler: on_interrupt, sa_mask: 0, sa_flags: SA_RESETHAND }
+}
+
+// The first Ctrl-C only sets a flag that the run loop polls, so the
+// interpreter can stop cleanly and report where it was instead of dying
+// mid-step. Without SA_RESTART it also ends a blocking read. SA_RESETHAND
+// puts the default handler back, so a second Ctrl-C always kills.
+#[cfg(any(
+    all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")),
+    all(target_os = "macos", any(target_arch = "x86_64", target_arch = "aarch64"))
+))]
+fn install_interrupt_handler() {
+    const SIGINT: i32 = 2;
+    extern "C" {
+        fn sigaction(signum: i32, act: *const SigAction, oldact: *mut SigAction) -> i32;
+    }
+    let action = interrupt_action();
+    // Without the handler Ctrl-C still ends the process, only without the
+    // report, so this is not worth aborting over.
+    if unsafe { sigaction(SIGINT, &action, std::ptr::null_mut()) } != 0 {
+        eprintln!("warning: could not install the Ctrl-C handler: {}", io::Error::last_os_error());
+    }
+}
+
+// Elsewhere Ctrl-C keeps its default behaviour and ends the process.
+#[cfg(not(any(
+    all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")),
+    all(target_os = "macos", any(target_arch = "x86_64", target_arch = "aarch64"))
+)))]
+fn install_interrupt_handler() {}
+
+const HIGHLIGHT_ON: &str = "\x1b[7m";
+const HIGHLIGHT_OFF: &str = "\x1b[0m";
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+fn show_debug_state(interpreter: &Interpreter) {
+    eprint!("{}", interpreter.render_codebox(HIGHLIGHT_ON, HIGHLIGHT_OFF));
+    eprintln!("{}", interpreter.trace_line());
+}
+
+// Interactive front-end for --debug. Commands are read through the
+// interpreter's own reader, so a program that uses g/G reads its input from
+// the same lines and nothing buffered for one is lost to the other.
+fn debug(interpreter: &mut Interpreter) -> Result<(), ArghError> {
+    let mut breakpoints: HashSet<Position> = HashSet::new();
+    let mut history: Vec<InterpreterState> = Vec::new();
+
+    interpreter.start()?;
+    show_debug_state(interpreter);
+
+    while interpreter.is_running() {
+        eprint!("(argh) ");
+        let mut line = String::new();
+        match interpreter.reader.read_line(&mut line) {
+            Err(_) if interpreter.interrupted() => return Err(ArghError::Interrupted),
+            Err(err) => return Err(ArghError::Io(err)),
+            Ok(0)    => return Ok(()),
+            Ok(_)    => {}
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["s"] => {
+                history.push(interpreter.snapshot());
+                interpreter.step()?;
+                show_debug_state(interpreter);
+            }
+            ["c"] => {
+                history.push(interpreter.snapshot());
+                interpreter.step()?;
+                while interpreter.is_running() && !breakpoints.contains(&interpreter.position()) {
+                    interpreter.step()?;
+                }
+                show_debug_state(interpreter);
+            }
+            ["b", x, y] => match (x.parse(), y.parse()) {
+                (Ok(x), Ok(y)) => {
+                    breakpoints.insert(Position { x, y });
+                    eprintln!("Breakpoint set at (x: {}, y: {})", x, y);
+                }
+                _ => eprintln!("Usage: b <x> <y>")
+            },
+            ["p"] => eprintln!("{:?}", interpreter.stack()),
+            ["u"] => match history.pop() {
+                Some(state) => {
+                    interpreter.restore(state);
+                    show_debug_state(interpreter);
+                }
+                None => eprintln!("Nothing to undo")
+            },
+            ["q"] => return Ok(()),
+            _ => {
+                eprintln!("s          step one instruction");
+                eprintln!("c          continue to the next breakpoint");
+                eprintln!("b <x> <y>  set a breakpoint");
+                eprintln!("p          print the stack");
+                eprintln!("u          undo the last s or c");
+                eprintln!("q          quit");
+            }
+        }
+    }
+
+    eprintln!("Program halted");
+    Ok(())
+}
+
+// Front-end for --visualize. Frames go to stderr so the program's own output
+// on stdout stays usable. Without a terminal there is nothing to redraw, so
+// every step is dumped after the previous one, with the current cell in
+// brackets and without waiting.
+fn visualize(interpreter: &mut Interpreter, frame_delay: Duration) -> Result<(), ArghError> {
+    let terminal = io::stderr().is_terminal();
+
+    interpreter.start()?;
+
+    while interpreter.is_running() {
+        if terminal {
+            eprint!("{}{}", CLEAR_SCREEN, interpreter.render_codebox(HIGHLIGHT_ON, HIGHLIGHT_OFF));
+        } else {
+            eprint!("{}", interpreter.render_codebox("[", "]"));
+        }
+        eprintln!("{}", interpreter.trace_line());
+
+        if terminal {
+            thread::sleep(frame_delay);
+        }
+        interpreter.step()?;
+    }
+
+    Ok(())
+}
+
+// Only names the options. README.md describes what each one does.
+const OPTIONS: &str = "\
+Loading:   --binary  --comments  --comment-prefix P  --no-expand-tabs
+           --tab-width N  --pad-with C  --check
+Running:   --wrap  --eof N  --input TEXT  --input-file PATH  --max-steps N
+           --stack-limit N  --timeout SECONDS  --detect-cycle  --fail-fast
+Output:    --flush-each  --numeric-output  --count-only  --quiet
+Reports:   --stats  --profile  --trace  --trace-from N  --hexdump
+           --dump-stack  --dump-codebox PATH
+Stepping:  --debug  --visualize  --fps N";
+
+fn usage() -> ! {
+    eprintln!("Usage: argh [options] <file.argh>...");
+    eprintln!("Pass - instead of a file name to read the program from stdin.");
+    eprintln!();
+    eprintln!("{}", OPTIONS);
+    process::exit(EXIT_USAGE);
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> T {
+    match value.as_ref().map(|v| v.parse()) {
+        Some(Ok(parsed)) => parsed,
+        _                => {
+            eprintln!("Invalid or missing value for {}", flag);
+            usage();
+        }
+    }
+}
+
+fn parse_seconds(flag: &str, value: Option<String>) -> Duration {
+    match Duration::try_from_secs_f64(parse_value(flag, value)) {
+        Ok(duration) => duration,
+        Err(_)       => {
+            eprintln!("Invalid or missing value for {}", flag);
+            usage();
+        }
+    }
+}
+
+// Turns frames per second into the delay between two frames.
+fn parse_fps(flag: &str, value: Option<String>) -> Duration {
+    let fps: f64 = parse_value(flag, value);
+    match Duration::try_from_secs_f64(1.0 / fps) {
+        Ok(delay) => delay,
+        Err(_)    => {
+            eprintln!("Invalid or missing value for {}", flag);
+            usage();
+        }
+    }
+}
+
+// A width of 0 would leave tabs as they are, which is what --no-expand-tabs
+// is for.
+fn parse_tab_width(flag: &str, value: Option<String>) -> usize {
+    match parse_value(flag, value) {
+        0     => {
+            eprintln!("Invalid or missing value for {}", flag);
+            usage();
+        }
+        width => width
+    }
+}
+
+struct Settings {
+    wrap:           bool,
+    dump_stack:     bool,
+    detect_cycle:   bool,
+    profile:        bool,
+    flush_each:     bool,
+    eof:            i32,
+    tab_width:      Option<usize>,
+    comment_prefix: Option<String>,
+    padding:        char,
+    check:          bool,
+    debug_mode:     bool,
+    visualize_mode: bool,
+    frame_delay:    Duration,
+    binary:         bool,
+    count_only:     bool,
+    stats:          bool,
+    quiet:          bool,
+    numeric_output: bool,
+    hexdump:        bool,
+    fail_fast:      bool,
+    input:          Option<String>,
+    input_file:     Option<String>,
+    dump_codebox:   Option<String>,
+    trace_from:     Option<u64>,
+    max_steps:      Option<u64>,
+    stack_limit:    Option<usize>,
+    timeout:        Option<Duration>
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            wrap:           false,
+            dump_stack:     false,
+            detect_cycle:   false,
+            profile:        false,
+            flush_each:     false,
+            eof:            0,
+            tab_width:      Some(8),
+            comment_prefix: None,
+            padding:        ' ',
+            check:          false,
+            debug_mode:     false,
+            visualize_mode: false,
+            frame_delay:    Duration::from_millis(100),
+            binary:         false,
+            count_only:     false,
+            stats:          false,
+            quiet:          false,
+            numeric_output: false,
+            hexdump:        false,
+            fail_fast:      false,
+            input:          None,
+            input_file:     None,
+            dump_codebox:   None,
+            trace_from:     None,
+            max_steps:      None,
+            stack_limit:    None,
+            timeout:        None
+        }
+    }
+}
+
+// Loads and runs one program and returns the exit code it should end with.
+// `stdin` is shared by every program of a batch, so input buffered while one
+// program read its lines is still there for the next.
+fn run_file(filename: &str, settings: &Settings, stdin: &mut Box<dyn BufRead>) -> i32 {
+    let read_from_stdin = filename == "-";
+    if settings.debug_mode && read_from_stdin {
+        eprintln!("--debug reads its commands from stdin, so the program has to come from a file");
+        return EXIT_USAGE;
+    }
+
+    let options = CodeboxOptions {
+        tab_width:      settings.tab_width,
+        comment_prefix: settings.comment_prefix.clone(),
+        padding:        settings.padding,
+        binary:         settings.binary
+    };
+
+    let loaded = if read_from_stdin {
+        let mut bytes = Vec::new();
+        stdin.read_to_end(&mut bytes).map(|_| Program::from_source(bytes, &options))
+    } else {
+        Program::from_file_with_options(filename, &options)
+    };
+
+    let program = match loaded {
+        Ok(program) => program,
+        Err(err)    => {
+            eprintln!("Could not load file '{}': {}", filename, err);
+            return EXIT_USAGE;
+        }
+    };
+
+    // --check lists every problem. A normal run only mentions the missing
+    // 'q', the other warnings are noise for a program that works.
+    if settings.check {
+        for warning in program.check() {
+            eprintln!("warning: {}", warning);
+        }
+        return EXIT_HALTED;
+    }
+
+    if !settings.quiet && !program.codebox().has_quit() && !program.codebox().is_empty() {
+        eprintln!("warning: {}", NO_QUIT_WARNING);
+    }
+
+    let mut input = settings.input.clone();
+    if let Some(ref path) = settings.input_file {
+        match fs::read_to_string(path) {
+            Ok(contents) => input = Some(contents),
+            Err(err)     => {
+                eprintln!("Could not load input file '{}': {}", path, err);
+                return EXIT_USAGE;
+            }
+        }
+    }
+
+    // A program read from stdin consumed it up to EOF, so there is no
+    // runtime input left for g/G. They see end of input straight away
+    // instead of blocking. Otherwise g/G borrow the shared reader for the run.
+    let borrows_stdin = input.is_none() && !read_from_stdin;
+    let reader: Box<dyn BufRead> = if let Some(input) = input {
+        Box::new(Cursor::new(input.into_bytes()))
+    } else if read_from_stdin {
+        Box::new(io::empty())
+    } else {
+        mem::replace(stdin, Box::new(io::empty()))
+    };
+
+    let mut interpreter = Interpreter::with_io(program, reader, BufWriter::new(io::stdout()))
+        .with_wrap(settings.wrap)
+        .with_flush_each(settings.flush_each || settings.debug_mode || settings.visualize_mode)
+        .with_eof(settings.eof)
+        .with_numeric_output(settings.numeric_output)
+        .with_max_steps(settings.max_steps)
+        .with_stack_limit(settings.stack_limit)
+        .with_timeout(settings.timeout)
+        .with_trace(settings.trace_from)
+        .with_cycle_detection(settings.detect_cycle)
+        .with_profiling(settings.profile)
+        .with_input_log(settings.debug_mode)
+        .with_interrupt(&INTERRUPTED);
+
+    if settings.count_only {
+        interpreter = interpreter.with_output(io::sink());
+    }
+
+    let result = if settings.debug_mode {
+        debug(&mut interpreter)
+    } else if settings.visualize_mode {
+        visualize(&mut interpreter, settings.frame_delay)
+    } else {
+        interpreter.run()
+    };
+
+    if borrows_stdin {
+        *stdin = mem::replace(&mut interpreter.reader, Box::new(io::empty()));
+    }
+
+    if settings.count_only || settings.stats {
+        eprintln!("Steps: {}", interpreter.steps_executed());
+        eprintln!("Characters: {}", interpreter.chars_written());
+        eprintln!("Bytes: {}", interpreter.bytes_written());
+    }
+
+    if settings.hexdump {
+        eprint!("{}", interpreter.codebox().hexdump());
+    }
+
+    if let Some(ref path) = settings.dump_codebox {
+        if let Err(err) = fs::write(path, interpreter.codebox().to_string()) {
+            eprintln!("Could not write codebox to '{}': {}", path, err);
+        }
+    }
+
+    if let Some(profile) = interpreter.profile() {
+        let mut counts: Vec<(&char, &u64)> = profile.iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+        eprintln!("Instruction  Count");
+        for (instruction, count) in counts {
+            eprintln!("{:<11}  {}", format!("'{}'", instruction), count);
+        }
+    }
+
+    if settings.dump_stack {
+        eprintln!("Stack (bottom to top):");
+        for value in interpreter.stack() {
+            eprintln!("{:>12} '{}'", value, Codebox::i32_as_char(*value).escape_debug());
+        }
+    }
+
+    if let Err(err) = result {
+        if !settings.quiet {
+            if let ArghError::Interrupted = err {
+                eprintln!("{}", interpreter.trace_line());
+            }
+            eprintln!("{}", interpreter.describe_error(&err));
+        }
+        return err.exit_code();
+    }
+
+    EXIT_HALTED
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let filename = &args[1];
+    let mut args = env::args().skip(1);
+    let mut settings = Settings::default();
+    let mut files: Vec<String> = Vec::new();
 
-    let file_contents = fs::read_to_string(filename).expect("Could not load file!");
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--count-only"     => settings.count_only = true,
+            "--stats"          => settings.stats = true,
+            "--quiet"          => settings.quiet = true,
+            "--numeric-output" => settings.numeric_output = true,
+            "--hexdump"        => settings.hexdump = true,
+            "--debug"          => settings.debug_mode = true,
+            "--visualize"      => settings.visualize_mode = true,
+            "--fps"            => settings.frame_delay = parse_fps(&arg, args.next()),
+            "--binary"         => settings.binary = true,
+            "--check"          => settings.check = true,
+            "--fail-fast"      => settings.fail_fast = true,
+            "--wrap"           => settings.wrap = true,
+            "--dump-codebox"   => settings.dump_codebox = Some(parse_value(&arg, args.next())),
+            "--dump-stack"     => settings.dump_stack = true,
+            "--detect-cycle"   => settings.detect_cycle = true,
+            "--no-expand-tabs" => settings.tab_width = None,
+            "--tab-width"      => settings.tab_width = Some(parse_tab_width(&arg, args.next())),
+            "--comments"       => settings.comment_prefix = Some(String::from("#")),
+            "--comment-prefix" => settings.comment_prefix = Some(parse_value(&arg, args.next())),
+            "--pad-with"       => settings.padding = parse_value(&arg, args.next()),
+            "--input"          => settings.input = Some(parse_value(&arg, args.next())),
+            "--input-file"     => settings.input_file = Some(parse_value(&arg, args.next())),
+            "--eof"            => settings.eof = parse_value(&arg, args.next()),
+            "--flush-each"     => settings.flush_each = true,
+            "--profile"        => settings.profile = true,
+            "--trace"          => settings.trace_from = Some(0),
+            "--trace-from"     => settings.trace_from = Some(parse_value(&arg, args.next())),
+            "--max-steps"      => settings.max_steps = Some(parse_value(&arg, args.next())),
+            "--stack-limit"    => settings.stack_limit = Some(parse_value(&arg, args.next())),
+            "--timeout"        => settings.timeout = Some(parse_seconds(&arg, args.next())),
+            "-"                => files.push(arg),
+            _ if arg.starts_with('-') => {
+                eprintln!("Unknown option {}", arg);
+                usage();
+            }
+            _                  => files.push(arg)
+        }
+    }
+
+    if files.is_empty() {
+        usage();
+    }
 
-    let codebox = Codebox::new(&file_contents);
+    install_interrupt_handler();
+    let mut stdin: Box<dyn BufRead> = Box::new(BufReader::new(InterruptibleStdin));
 
-    let mut interpreter = Interpreter::new(codebox);
+    if files.len() == 1 {
+        process::exit(run_file(&files[0], &settings, &mut stdin));
+    }
 
-    interpreter.run();
+    // Every program's output stays on stdout, the separators and the
+    // summary go to stderr next to the error reports.
+    let mut passed = 0;
+    let mut aborted = 0;
+    let mut exit_code = EXIT_HALTED;
+
+    for filename in files.iter() {
+        eprintln!("==> {} <==", filename);
+        let code = run_file(filename, &settings, &mut stdin);
+
+        if code == EXIT_HALTED {
+            passed += 1;
+            eprintln!("--> halted");
+            continue;
+        }
+
+        aborted += 1;
+        eprintln!("--> aborted with exit code {}", code);
+        if exit_code == EXIT_HALTED {
+            exit_code = code;
+        }
+        if settings.fail_fast || code == EXIT_INTERRUPTED {
+            break;
+        }
+    }
+
+    let skipped = files.len() - passed - aborted;
+    if skipped > 0 {
+        eprintln!("{} passed, {} aborted, {} not run", passed, aborted, skipped);
+    } else {
+        eprintln!("{} passed, {} aborted", passed, aborted);
+    }
+    process::exit(exit_code);
 }
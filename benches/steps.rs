@@ -0,0 +1,61 @@
+// Runs the fibonacci example over and over, with its output discarded, until
+// a fixed number of instructions has been executed and reports how many
+// steps per second the interpreter manages.
+//
+//     rustc -O benches/steps.rs -o steps && ./steps
+//
+// Run it from the repository root. Compare numbers from the same machine
+// only, and build with -O, otherwise the result means little.
+
+#[allow(dead_code)]
+#[path = "../argh.rs"]
+mod argh;
+
+use std::io::{self, Cursor};
+use std::time::{Duration, Instant};
+
+use argh::{ArghError, Codebox, Interpreter};
+
+const PROGRAM: &str = include_str!("../argh_scipts/fibonacci.agh");
+const STEPS: u64 = 100_000_000;
+const ROUNDS: usize = 5;
+
+fn run_steps(codebox: &Codebox, steps: u64) -> Duration {
+    let started = Instant::now();
+    let mut remaining = steps;
+
+    while remaining > 0 {
+        let mut interpreter = Interpreter::new(codebox.clone())
+            .with_input(Cursor::new(Vec::new()))
+            .with_output(io::sink())
+            .with_max_steps(Some(remaining));
+
+        match interpreter.run() {
+            Ok(()) | Err(ArghError::StepLimit { .. }) => {}
+            Err(err) => panic!("benchmark program failed: {}", err)
+        }
+        remaining -= interpreter.steps_executed();
+    }
+
+    started.elapsed()
+}
+
+fn main() {
+    let codebox = Codebox::new(PROGRAM);
+
+    // Warm up caches and the allocator before timing anything.
+    run_steps(&codebox, STEPS / 10);
+
+    let mut rounds: Vec<Duration> = (0..ROUNDS).map(|_| run_steps(&codebox, STEPS)).collect();
+    rounds.sort();
+
+    for (name, elapsed) in [("fastest", rounds[0]), ("median", rounds[ROUNDS / 2])].iter() {
+        println!(
+            "{:8} {:>12.0} steps/s ({} steps in {:.3}s)",
+            name,
+            STEPS as f64 / elapsed.as_secs_f64(),
+            STEPS,
+            elapsed.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,474 @@
+// Runs every program in tests/fixtures and compares its output with the
+// matching `.out` file. An optional `.in` file is fed to `g`/`G`.
+//
+//     rustc --test tests/integration.rs -o integration && ./integration
+//
+// Run it from the repository root so the fixture paths resolve.
+
+#[allow(dead_code)]
+#[path = "../argh.rs"]
+mod argh;
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, BufReader, Cursor, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use argh::{run_to_string, ArghError, Codebox, CodeboxOptions, Direction, Interpreter, Position, Program, SharedBuffer};
+
+const FIXTURES: &str = "tests/fixtures";
+const MAX_STEPS: u64 = 100_000;
+
+fn run_program(source: &str, input: &str) -> Result<String, ArghError> {
+    run_to_string(source, input, MAX_STEPS)
+}
+
+// Interpreter::new would hook up the real stdin and stdout. Tests that care
+// about either replace them with with_input/with_output.
+fn in_memory<C: Into<Codebox>>(codebox: C) -> Interpreter {
+    Interpreter::with_io(codebox, Cursor::new(Vec::new()), SharedBuffer::default())
+}
+
+#[test]
+fn fixtures() {
+    let mut failures = Vec::new();
+
+    for entry in fs::read_dir(FIXTURES).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|ext| ext != "agh") {
+            continue;
+        }
+
+        let source = fs::read_to_string(&path).unwrap();
+        let input = fs::read_to_string(path.with_extension("in")).unwrap_or_default();
+        let expected = fs::read_to_string(path.with_extension("out"))
+            .unwrap_or_else(|_| panic!("{} has no .out file", path.display()));
+
+        let output = run_program(&source, &input)
+            .unwrap_or_else(|err| panic!("{} failed: {}", path.display(), err));
+        if output != expected {
+            failures.push(format!("{}: expected {:?}, got {:?}", path.display(), expected, output));
+        }
+    }
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn move_until_without_match_stops_at_edge() {
+    let mut interpreter = in_memory(Codebox::new("lsLbc\n a"));
+    let err = interpreter.run().unwrap_err();
+    assert!(matches!(err, ArghError::NoMatchBeforeEdge));
+    assert_eq!(
+        interpreter.describe_error(&err),
+        "Aargh! at (2,0) heading East: 'L' — reached the edge of the codebox without finding the top of the stack"
+    );
+}
+
+#[test]
+fn move_until_without_match_stops_after_one_lap_when_wrapping() {
+    let mut interpreter = in_memory(Codebox::new("lsLbc\n a")).with_wrap(true);
+    assert!(matches!(interpreter.run(), Err(ArghError::NoMatchBeforeEdge)));
+    assert_eq!(interpreter.position(), Position { x: 2, y: 0 });
+}
+
+#[test]
+fn neighbouring_cells_wrap_with_wrap() {
+    let output = SharedBuffer::default();
+    let mut interpreter = in_memory(Codebox::new("lPSq\n\n Ah"))
+        .with_output(output.clone())
+        .with_wrap(true);
+    interpreter.run().unwrap();
+
+    assert_eq!(String::from_utf8(output.contents()).unwrap(), "A");
+    assert_eq!(interpreter.stack(), &['h' as i32]);
+
+    let mut strict = in_memory(Codebox::new("lPSq\n\n Ah"));
+    assert!(matches!(strict.run(), Err(ArghError::OutOfBounds)));
+}
+
+#[test]
+fn move_until_with_empty_stack_underflows() {
+    let result = run_program("Lq", "");
+    assert!(matches!(result, Err(ArghError::StackUnderflow)));
+}
+
+// Ends after its first line, then has more to give, like a terminal after
+// Ctrl-D.
+struct ReopeningReader {
+    reads: usize
+}
+
+impl Read for ReopeningReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        let data: &[u8] = match self.reads {
+            1 => b"a\n",
+            2 => b"",
+            _ => b"z\n"
+        };
+        buf[..data.len()].copy_from_slice(data);
+        Ok(data.len())
+    }
+}
+
+#[test]
+fn reading_past_the_end_of_input_gives_eof() {
+    let mut interpreter = in_memory(Codebox::new("lggggggq\n        "))
+        .with_input(BufReader::new(ReopeningReader { reads: 0 }))
+        .with_eof(-7);
+    interpreter.run().unwrap();
+
+    let cells: Vec<i32> = (1..7)
+        .map(|x| *interpreter.codebox().get_instruction(&Position { x, y: 1 }).unwrap())
+        .collect();
+    assert_eq!(cells, vec!['a' as i32, '\n' as i32, -7, ' ' as i32, -7, -7]);
+}
+
+#[test]
+fn restoring_a_snapshot_rewinds_input_and_step_count() {
+    let mut interpreter = in_memory(Codebox::new("lggq\n    "))
+        .with_input(Cursor::new(b"ab\n".to_vec()))
+        .with_input_log(true);
+    interpreter.start().unwrap();
+    interpreter.step().unwrap();
+
+    let state = interpreter.snapshot();
+    interpreter.step().unwrap();
+    interpreter.restore(state);
+    assert_eq!(interpreter.steps_executed(), 1);
+
+    interpreter.step().unwrap();
+    interpreter.step().unwrap();
+    let cells: Vec<i32> = (1..3)
+        .map(|x| *interpreter.codebox().get_instruction(&Position { x, y: 1 }).unwrap())
+        .collect();
+    assert_eq!(cells, vec!['a' as i32, 'b' as i32]);
+}
+
+#[test]
+fn turning_with_empty_stack_underflows() {
+    assert!(matches!(run_program("lxq", ""), Err(ArghError::StackUnderflow)));
+    assert!(matches!(run_program("lXq", ""), Err(ArghError::StackUnderflow)));
+}
+
+// y grows downwards like the lines of the source file, so north is -y and
+// east is +x.
+#[test]
+fn directions_move_the_way_they_are_named() {
+    let origin = Position { x: 5, y: 5 };
+    assert_eq!(origin + Direction::NORTH, Position { x: 5, y: 4 });
+    assert_eq!(origin + Direction::SOUTH, Position { x: 5, y: 6 });
+    assert_eq!(origin + Direction::WEST, Position { x: 4, y: 5 });
+    assert_eq!(origin + Direction::EAST, Position { x: 6, y: 5 });
+
+    let clockwise = [Direction::NORTH, Direction::EAST, Direction::SOUTH, Direction::WEST];
+    for i in 0..4 {
+        assert_eq!(clockwise[i].turn_cw(), clockwise[(i + 1) % 4]);
+        assert_eq!(clockwise[(i + 1) % 4].turn_ccw(), clockwise[i]);
+        assert_eq!(clockwise[i].reverse(), clockwise[(i + 2) % 4]);
+    }
+
+    let names: Vec<String> = clockwise.iter().map(|d| d.to_string()).collect();
+    assert_eq!(names, vec!["North", "East", "South", "West"]);
+
+    // x turns right from east, so the pointer heads south onto the q.
+    assert_eq!(run_program("lsx\n Aq", "").unwrap(), "");
+}
+
+#[test]
+fn errors_report_position_instruction_and_direction() {
+    let mut interpreter = in_memory(Codebox::new("lDq"));
+    let err = interpreter.run().unwrap_err();
+    assert_eq!(
+        interpreter.describe_error(&err),
+        "Aargh! at (1,0) heading East: 'D' — stack underflow"
+    );
+}
+
+#[test]
+fn hexdump_shows_cell_values() {
+    assert_eq!(Codebox::from_bytes(b"l\x00q\n\xff").hexdump(), "6c 00 71\nff 20 20\n");
+
+    let mut codebox = Codebox::new("lq");
+    codebox.set_instruction(&Position { x: 1, y: 0 }, -300).unwrap();
+    assert_eq!(codebox.hexdump(), "  6c -12c\n");
+}
+
+#[test]
+fn i32_as_char_boundaries() {
+    let cases = [
+        (-1, ' '),
+        (0, '\0'),
+        (1, '\u{1}'),
+        (254, 'þ'),
+        (255, 'ÿ'),
+        (256, 'Ā'),
+        (0xD800, ' '),
+        (0x10FFFF, '\u{10FFFF}'),
+        (0x110000, ' '),
+    ];
+    for &(value, expected) in cases.iter() {
+        assert_eq!(Codebox::i32_as_char(value), expected, "value {}", value);
+    }
+}
+
+// f/F store any value. Reading it back gives the exact value, only executing
+// a cell that is not a character is an error.
+#[test]
+fn negative_write_reads_back_exactly() {
+    let mut interpreter = in_memory(Codebox::new("lsrfj\n AF j\n  qSh"));
+    interpreter.run().unwrap();
+    assert_eq!(interpreter.stack(), &[-5]);
+}
+
+#[test]
+fn write_above_255_prints_as_its_character() {
+    assert_eq!(run_program("lsafj\n AĀ j\n  qPh", "").unwrap(), "Ł");
+}
+
+#[test]
+fn executing_a_non_character_cell_reports_its_value() {
+    let mut interpreter = in_memory(Codebox::new("lsrfj\n AF h"));
+    let err = interpreter.run().unwrap_err();
+    assert!(matches!(err, ArghError::InvalidCell(-5)));
+    assert_eq!(
+        interpreter.describe_error(&err),
+        "Aargh! at (3,1) heading West — the cell holds -5, which is not a character"
+    );
+}
+
+#[test]
+fn has_quit_looks_for_a_q_cell() {
+    assert!(Codebox::new("lpj\n  q").has_quit());
+    assert!(!Codebox::new("lpj\n  Q").has_quit());
+}
+
+#[test]
+fn program_bundles_source_and_codebox() {
+    let program: Program = "lDh\n z".parse().unwrap();
+    assert_eq!(program.source(), b"lDh\n z");
+    assert_eq!((program.width(), program.height()), (3, 2));
+    assert_eq!(program.path(), None);
+    assert_eq!(program.check().len(), 2);
+
+    let mut interpreter = in_memory(program);
+    assert!(matches!(interpreter.run(), Err(ArghError::StackUnderflow)));
+}
+
+#[test]
+fn nul_cells_are_reported_escaped() {
+    let program: Program = "l\0q".parse().unwrap();
+    assert_eq!(program.check(), vec!["'\\0' at (x: 1, y: 0) is not an instruction".to_string()]);
+
+    let mut interpreter = in_memory(program);
+    assert!(matches!(interpreter.run(), Err(ArghError::UnknownInstruction('\0'))));
+}
+
+#[test]
+fn program_from_file_remembers_the_path() {
+    let path = Path::new(FIXTURES).join("hello_world.agh");
+    let program = Program::from_file(&path).unwrap();
+    assert_eq!(program.path(), Some(path.as_path()));
+    assert!(program.codebox().has_quit());
+}
+
+#[test]
+fn from_reader_builds_the_same_grid_as_new() {
+    let source = "lpj\r\n\tq\n€";
+    let streamed = Codebox::from_reader(Cursor::new(source.as_bytes())).unwrap();
+    assert_eq!(streamed.to_string(), Codebox::new(source).to_string());
+}
+
+#[test]
+fn tabs_expand_to_the_configured_width() {
+    let options = CodeboxOptions { tab_width: Some(4), ..Default::default() };
+    assert_eq!(Codebox::with_options("l\tq\r\nab\tc", &options).to_string(), "l   q\nab  c\n");
+}
+
+#[test]
+fn a_trailing_carriage_return_is_dropped() {
+    assert_eq!(Codebox::new("Lq\r\nab\r").to_string(), "Lq\nab\n");
+}
+
+#[test]
+fn write_string_lays_out_data_cells() {
+    let mut codebox = Codebox::new("lpppq\n     ");
+    codebox.write_string(Position { x: 1, y: 1 }, Direction::EAST, "Hey").unwrap();
+    assert_eq!(run_program(&codebox.to_string(), "").unwrap(), "Hey");
+
+    codebox.write_string(Position { x: 3, y: 1 }, Direction::WEST, "Hey").unwrap();
+    assert_eq!(run_program(&codebox.to_string(), "").unwrap(), "yeH");
+}
+
+#[test]
+fn write_string_that_does_not_fit_writes_nothing() {
+    let mut codebox = Codebox::new("lq ");
+    let result = codebox.write_string(Position { x: 1, y: 0 }, Direction::EAST, "abc");
+    assert!(matches!(result, Err(ArghError::OutOfBounds)));
+    assert_eq!(codebox.to_string(), "lq \n");
+}
+
+#[test]
+fn short_rows_are_padded_with_the_configured_character() {
+    let options = CodeboxOptions { padding: 'q', ..Default::default() };
+    let codebox = Codebox::with_options("lllj\nl", &options);
+    assert_eq!(codebox.to_string(), "lllj\nlqqq\n");
+
+    // The pointer walks off the end of the short row onto padding and halts.
+    let mut interpreter = in_memory(codebox).with_start_position(Position { x: 0, y: 1 }).unwrap();
+    interpreter.run().unwrap();
+}
+
+#[test]
+fn comment_lines_are_removed_before_the_grid_is_built() {
+    let options = CodeboxOptions { comment_prefix: Some(String::from("#")), ..Default::default() };
+    let codebox = Codebox::with_options("# comment\n  # indented\nlDq #", &options);
+    assert_eq!(codebox.height(), 1);
+
+    let mut interpreter = in_memory(codebox);
+    let err = interpreter.run().unwrap_err();
+    assert_eq!(
+        interpreter.describe_error(&err),
+        "Aargh! at (1,0) heading East: 'D' — stack underflow"
+    );
+}
+
+#[test]
+fn state_json_describes_the_interpreter() {
+    let mut interpreter = in_memory(Codebox::new("lsdq\n \"\\"));
+    interpreter.run().unwrap();
+    assert_eq!(
+        interpreter.state_json(),
+        concat!(
+            r#"{"codebox":["lsdq"," \"\\ "],"stack":[34,34],"position":{"x":4,"y":0},"#,
+            r#""direction":"East","running":false}"#
+        )
+    );
+}
+
+#[test]
+fn steps_yield_every_instruction_until_halt() {
+    let interpreter = in_memory(Codebox::new("lsj\n Aq"));
+    let executed: Vec<(char, Position, Vec<i32>)> = interpreter.steps()
+        .map(|step| step.unwrap())
+        .map(|step| (step.instruction, step.position, step.stack))
+        .collect();
+
+    assert_eq!(
+        executed,
+        vec![
+            ('l', Position { x: 0, y: 0 }, vec![]),
+            ('s', Position { x: 1, y: 0 }, vec![65]),
+            ('j', Position { x: 2, y: 0 }, vec![65]),
+            ('q', Position { x: 2, y: 1 }, vec![65]),
+        ]
+    );
+}
+
+#[test]
+fn steps_end_with_the_error() {
+    let interpreter = in_memory(Codebox::new("lDq"));
+    let steps: Vec<Result<_, _>> = interpreter.steps().collect();
+
+    assert_eq!(steps.len(), 2);
+    assert!(matches!(steps[1], Err(ArghError::StackUnderflow)));
+}
+
+#[test]
+fn diagnostics_stay_out_of_the_output() {
+    let output = SharedBuffer::default();
+    let mut interpreter = in_memory(Codebox::new("lpDq\n A"))
+        .with_output(output.clone())
+        .with_trace(Some(0))
+        .with_profiling(true);
+    assert!(interpreter.run().is_err());
+
+    assert_eq!(String::from_utf8(output.contents()).unwrap(), "A");
+}
+
+#[test]
+fn numeric_output_prints_values() {
+    let output = SharedBuffer::default();
+    let mut interpreter = in_memory(Codebox::new("lppq\n A\u{7}"))
+        .with_output(output.clone())
+        .with_numeric_output(true);
+    interpreter.run().unwrap();
+
+    assert_eq!(String::from_utf8(output.contents()).unwrap(), "65 7 ");
+    assert_eq!(interpreter.chars_written(), 2);
+    assert_eq!(interpreter.bytes_written(), 5);
+}
+
+#[test]
+fn counters_track_steps_characters_and_bytes() {
+    let mut interpreter = in_memory(Codebox::new("lppj\n €あq"))
+        .with_output(std::io::sink());
+    interpreter.run().unwrap();
+
+    assert_eq!(interpreter.steps_executed(), 5);
+    assert_eq!(interpreter.chars_written(), 2);
+    assert_eq!(interpreter.bytes_written(), 6);
+}
+
+struct FailingWriter;
+
+impl Write for FailingWriter {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::other("disk full"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn failed_writes_are_not_counted() {
+    let mut interpreter = in_memory(Codebox::new("lpq\n A"))
+        .with_output(FailingWriter);
+    assert!(matches!(interpreter.run(), Err(ArghError::Io(_))));
+
+    assert_eq!(interpreter.chars_written(), 0);
+    assert_eq!(interpreter.bytes_written(), 0);
+}
+
+#[test]
+fn observer_sees_every_executed_instruction() {
+    let executed = Rc::new(RefCell::new(Vec::new()));
+    let recorder = executed.clone();
+
+    let mut interpreter = in_memory(Codebox::new("lsj\n Aq"))
+        .with_observer(move |context| {
+            recorder.borrow_mut().push((context.instruction, context.stack.to_vec()));
+        });
+    interpreter.run().unwrap();
+
+    assert_eq!(
+        *executed.borrow(),
+        vec![('l', vec![]), ('s', vec![65]), ('j', vec![65]), ('q', vec![65])]
+    );
+}
+
+#[test]
+fn observer_sees_where_a_scan_started() {
+    let positions = Rc::new(RefCell::new(Vec::new()));
+    let recorder = positions.clone();
+
+    let mut interpreter = in_memory(Codebox::new("lsL  jq\n j"))
+        .with_observer(move |context| {
+            recorder.borrow_mut().push((context.instruction, context.position));
+        });
+    interpreter.run().unwrap();
+
+    assert_eq!(
+        *positions.borrow(),
+        vec![
+            ('l', Position { x: 0, y: 0 }),
+            ('s', Position { x: 1, y: 0 }),
+            ('L', Position { x: 2, y: 0 }),
+            ('q', Position { x: 6, y: 0 }),
+        ]
+    );
+}